@@ -0,0 +1,150 @@
+//! Shared attack/decay/sustain/release envelope driver used by every gated audio source.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Tracks where a voice sits in its attack/decay/sustain/release cycle.
+///
+/// The envelope is driven by a shared `gate`: while it's `true` the source runs through
+/// attack, decay and sustain; once the gate flips to `false` the source ramps down from
+/// whatever level it was at over `release_secs`, and [`EnvelopeState::next_level`] returns `None`
+/// once that ramp completes so the caller knows the voice is done.
+pub struct EnvelopeState {
+    sample_rate: u32,
+    sample_idx: u64,
+    gate: Arc<AtomicBool>,
+    attack_secs: f32,
+    decay_secs: f32,
+    sustain_level: f32,
+    release_secs: f32,
+    released_at: Option<u64>,
+    level_at_release: f32,
+}
+
+impl EnvelopeState {
+    pub fn new(
+        sample_rate: u32,
+        gate: Arc<AtomicBool>,
+        attack_secs: f32,
+        decay_secs: f32,
+        sustain_level: f32,
+        release_secs: f32,
+    ) -> Self {
+        EnvelopeState {
+            sample_rate,
+            sample_idx: 0,
+            gate,
+            attack_secs,
+            decay_secs,
+            sustain_level,
+            release_secs,
+            released_at: None,
+            level_at_release: 0.0,
+        }
+    }
+
+    /// Returns the envelope amplitude for the current sample and advances to the next one, or
+    /// `None` once release has fully decayed.
+    pub fn next_level(&mut self) -> Option<f32> {
+        let t = self.sample_idx as f32 / self.sample_rate as f32;
+        let held_level = if t < self.attack_secs {
+            t / self.attack_secs.max(1e-6)
+        } else if t < self.attack_secs + self.decay_secs {
+            let decay_t = (t - self.attack_secs) / self.decay_secs.max(1e-6);
+            1.0 - decay_t * (1.0 - self.sustain_level)
+        } else {
+            self.sustain_level
+        };
+
+        let level = if self.gate.load(Ordering::Relaxed) {
+            self.released_at = None;
+            Some(held_level)
+        } else {
+            let released_at = *self.released_at.get_or_insert(self.sample_idx);
+            if released_at == self.sample_idx {
+                self.level_at_release = held_level;
+            }
+            let release_t = (self.sample_idx - released_at) as f32 / self.sample_rate as f32;
+            if release_t >= self.release_secs {
+                None
+            } else {
+                let frac = release_t / self.release_secs.max(1e-6);
+                Some(self.level_at_release * (1.0 - frac))
+            }
+        };
+
+        self.sample_idx += 1;
+        level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EnvelopeState;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    /// 10 samples/sec so attack/decay/release boundaries land on whole sample indices and are
+    /// easy to reason about by hand.
+    const SAMPLE_RATE: u32 = 10;
+
+    fn held_envelope(
+        attack_secs: f32,
+        decay_secs: f32,
+        sustain_level: f32,
+        release_secs: f32,
+    ) -> (EnvelopeState, Arc<AtomicBool>) {
+        let gate = Arc::new(AtomicBool::new(true));
+        let envelope = EnvelopeState::new(
+            SAMPLE_RATE,
+            gate.clone(),
+            attack_secs,
+            decay_secs,
+            sustain_level,
+            release_secs,
+        );
+        (envelope, gate)
+    }
+
+    #[test]
+    fn attack_ramps_linearly_to_its_peak() {
+        let (mut envelope, _gate) = held_envelope(0.2, 0.2, 0.5, 0.3);
+        assert_eq!(envelope.next_level(), Some(0.0)); // t=0.0
+        assert_eq!(envelope.next_level(), Some(0.5)); // t=0.1, halfway through attack
+    }
+
+    #[test]
+    fn decay_ramps_down_to_sustain_level() {
+        let (mut envelope, _gate) = held_envelope(0.2, 0.2, 0.5, 0.3);
+        envelope.next_level(); // t=0.0, attack
+        envelope.next_level(); // t=0.1, attack
+        assert_eq!(envelope.next_level(), Some(1.0)); // t=0.2, decay starts at the peak
+        assert_eq!(envelope.next_level(), Some(0.75)); // t=0.3, halfway through decay
+        assert_eq!(envelope.next_level(), Some(0.5)); // t=0.4, settled into sustain
+        assert_eq!(envelope.next_level(), Some(0.5)); // t=0.5, holds sustain indefinitely
+    }
+
+    #[test]
+    fn release_ramps_down_from_sustain_and_then_ends() {
+        let (mut envelope, gate) = held_envelope(0.2, 0.2, 0.5, 0.3);
+        for _ in 0..6 {
+            envelope.next_level(); // run through attack, decay, and into sustain
+        }
+        gate.store(false, std::sync::atomic::Ordering::Relaxed);
+
+        assert_eq!(envelope.next_level(), Some(0.5)); // release starts at the sustain level
+        assert!((envelope.next_level().unwrap() - 0.3333333).abs() < 1e-4); // a third released
+        assert!((envelope.next_level().unwrap() - 0.1666667).abs() < 1e-4); // two-thirds released
+        assert_eq!(envelope.next_level(), None); // fully released after release_secs
+    }
+
+    #[test]
+    fn release_can_interrupt_attack_before_it_peaks() {
+        let (mut envelope, gate) = held_envelope(0.2, 0.2, 0.5, 0.3);
+        envelope.next_level(); // t=0.0, still in attack
+        gate.store(false, std::sync::atomic::Ordering::Relaxed);
+
+        // Capture the attack's partial level (0.5, at t=0.1) rather than waiting for the peak.
+        assert_eq!(envelope.next_level(), Some(0.5));
+    }
+}
@@ -1,55 +1,88 @@
-use midir::{Ignore, MidiInput, MidiInputPort};
-use rodio::{source::SineWave, OutputStream, Sink};
+mod envelope;
+mod midi_input;
+mod oscillator;
+mod recorder;
+mod soundfont;
+mod tuning;
+
+use oscillator::EnvelopedOscillator;
+use recorder::MidiRecorder;
+use rodio::{OutputStream, Sink};
+use soundfont::SoundFont;
 use std::collections::HashMap;
 use std::error::Error;
 use std::io::stdin;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tuning::Tuning;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    // Initialize the MIDI input
-    let mut midi_in = MidiInput::new("midir reading input")?;
-    midi_in.ignore(Ignore::None);
+    // Initialize audio output
+    let (_stream, stream_handle) = OutputStream::try_default()?;
+    let mut synth = Synthesizer::new(stream_handle);
+
+    // An optional .sf2 bank can be passed as the first argument; notes fall back to the
+    // built-in oscillator when none is given or it fails to load. A Scala scale/keyboard
+    // mapping pair can follow as the second and third arguments; notes fall back to 12-TET
+    // when neither is given. A MIDI port name/substring can follow as the fourth argument to
+    // skip the interactive port picker.
+    let args: Vec<String> = std::env::args().collect();
+
+    if let Some(soundfont_path) = args.get(1) {
+        match synth.load_soundfont(Path::new(soundfont_path)) {
+            Ok(()) => println!("Loaded soundfont: {}", soundfont_path),
+            Err(err) => println!(
+                "Failed to load soundfont '{}': {} (falling back to oscillator)",
+                soundfont_path, err
+            ),
+        }
+    }
 
-    // Get available MIDI input ports
-    let in_ports = midi_in.ports();
-    if in_ports.is_empty() {
-        println!("No available MIDI input ports.");
-        return Ok(());
+    if let (Some(scl_path), Some(kbm_path)) = (args.get(2), args.get(3)) {
+        match synth.load_tuning(Path::new(scl_path), Path::new(kbm_path)) {
+            Ok(()) => println!("Loaded tuning: {} / {}", scl_path, kbm_path),
+            Err(err) => println!(
+                "Failed to load tuning '{}' / '{}': {} (falling back to 12-TET)",
+                scl_path, kbm_path, err
+            ),
+        }
     }
 
-    // Select the first available port
-    let in_port: &MidiInputPort = &in_ports[0];
+    let port_name = midi_input::select_port_name(args.get(4).map(String::as_str))?;
 
-    println!(
-        "Opening connection to port: {}",
-        midi_in.port_name(in_port)?
-    );
+    let synth = Arc::new(Mutex::new(synth));
+    // Records every decoded message so the performance can be saved as a .mid file on exit.
+    let recorder = Arc::new(Mutex::new(MidiRecorder::new()));
 
-    // Initialize audio output
-    let (_stream, stream_handle) = OutputStream::try_default()?;
-    let mut synth = Synthesizer::new(stream_handle);
+    // A background thread waits for the user to press Enter so the main thread stays free to
+    // poll for MIDI device disconnects while a note could still be playing.
+    let (exit_tx, exit_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = String::new();
+        let _ = stdin().read_line(&mut input);
+        let _ = exit_tx.send(());
+    });
 
-    // Define a callback to handle incoming MIDI messages
-    let in_port_name = midi_in.port_name(in_port)?;
-    let _conn_in = midi_in.connect(
-        in_port,
-        "midir-read-input",
-        move |stamp, message, _| {
-            println!("{}: {:?} (len = {})", stamp, message, message.len());
-            decode_midi_message(&mut synth, message);
-        },
-        (),
-    )?;
-
-    println!(
-        "Connection open, reading MIDI input from '{}'. Press Enter to exit...",
-        in_port_name
-    );
-
-    // Wait for user input to exit
-    let mut input = String::new();
-    stdin().read_line(&mut input)?;
+    let synth_cb = synth.clone();
+    let recorder_cb = recorder.clone();
+    midi_input::run_with_reconnect(&port_name, &exit_rx, move |stamp, message| {
+        println!("{}: {:?} (len = {})", stamp, message, message.len());
+        recorder_cb.lock().unwrap().record(stamp, message);
+        decode_midi_message(&mut synth_cb.lock().unwrap(), message);
+    })?;
 
     println!("Closing connection");
+
+    let recorder = recorder.lock().unwrap();
+    if !recorder.is_empty() {
+        let path = Path::new("recording.mid");
+        recorder.write_smf(path)?;
+        println!("Saved recording to {}", path.display());
+    }
+
     Ok(())
 }
 
@@ -60,22 +93,20 @@ fn decode_midi_message(synth: &mut Synthesizer, message: &[u8]) {
 
     match message[0] {
         0x80..=0x8F => {
+            let channel = message[0] & 0x0F;
             println!(
                 "Note Off: channel={}, note={}, velocity={}",
-                message[0] & 0x0F,
-                message[1],
-                message[2]
+                channel, message[1], message[2]
             );
-            synth.note_off(message[1]);
+            synth.note_off(channel, message[1]);
         }
         0x90..=0x9F => {
+            let channel = message[0] & 0x0F;
             println!(
                 "Note On: channel={}, note={}, velocity={}",
-                message[0] & 0x0F,
-                message[1],
-                message[2]
+                channel, message[1], message[2]
             );
-            synth.note_on(message[1], message[2]);
+            synth.note_on(channel, message[1], message[2]);
         }
         0xA0..=0xAF => println!(
             "Polyphonic Key Pressure: channel={}, note={}, pressure={}",
@@ -83,17 +114,19 @@ fn decode_midi_message(synth: &mut Synthesizer, message: &[u8]) {
             message[1],
             message[2]
         ),
-        0xB0..=0xBF => println!(
-            "Control Change: channel={}, controller={}, value={}",
-            message[0] & 0x0F,
-            message[1],
-            message[2]
-        ),
-        0xC0..=0xCF => println!(
-            "Program Change: channel={}, program={}",
-            message[0] & 0x0F,
-            message[1]
-        ),
+        0xB0..=0xBF => {
+            let channel = message[0] & 0x0F;
+            println!(
+                "Control Change: channel={}, controller={}, value={}",
+                channel, message[1], message[2]
+            );
+            synth.control_change(channel, message[1], message[2]);
+        }
+        0xC0..=0xCF => {
+            let channel = message[0] & 0x0F;
+            println!("Program Change: channel={}, program={}", channel, message[1]);
+            synth.program_change(channel, message[1]);
+        }
         0xD0..=0xDF => println!(
             "Channel Pressure: channel={}, pressure={}",
             message[0] & 0x0F,
@@ -112,10 +145,45 @@ fn decode_midi_message(synth: &mut Synthesizer, message: &[u8]) {
     }
 }
 
+/// MIDI controller number for the sustain pedal (hold all notes released while it's down).
+const CC_SUSTAIN_PEDAL: u8 = 64;
+/// MIDI controller number for channel volume.
+const CC_CHANNEL_VOLUME: u8 = 7;
+
+/// A voice currently occupying a sink: its playback handle, base frequency (for pitch-bend
+/// retuning), and the gate used to trigger its release phase. `freq_cell` is the shared
+/// frequency cell of a live [`EnvelopedOscillator`], letting pitch bend retune it in place;
+/// it's `None` for a sampled [`soundfont::SoundFontVoice`], which isn't retuned.
+struct Voice {
+    sink: Sink,
+    channel: u8,
+    base_frequency: f32,
+    velocity_gain: f32,
+    gate: Arc<AtomicBool>,
+    freq_cell: Option<Arc<AtomicU32>>,
+}
+
 struct Synthesizer {
     stream_handle: rodio::OutputStreamHandle,
-    sinks: HashMap<u8, (Sink, f32)>, // Store the sink and the base frequency
+    sinks: HashMap<(u8, u8), Voice>,
+    // Voices bumped out of `sinks` by a retrigger on the same (channel, note); kept around,
+    // already releasing, until their sink empties on its own so the cutoff isn't audible.
+    retiring: Vec<Voice>,
     pitch_bend_value: i16,
+    attack_secs: f32,
+    decay_secs: f32,
+    sustain_level: f32,
+    release_secs: f32,
+    soundfont: Option<SoundFont>,
+    tuning: Option<Tuning>,
+    channel_programs: HashMap<u8, u8>,
+    channel_volumes: HashMap<u8, u8>,
+    sustain_pedal_down: HashMap<u8, bool>,
+    // Gates of voices released with the sustain pedal held, kept sounding until the pedal
+    // lifts. Keyed by the voice's own gate rather than its note, so that if the same key is
+    // struck again before the pedal comes up, lifting the pedal releases the original
+    // (now-retiring) voice instead of the new one now occupying that note.
+    held_by_pedal: HashMap<u8, Vec<Arc<AtomicBool>>>,
 }
 
 impl Synthesizer {
@@ -123,38 +191,187 @@ impl Synthesizer {
         Synthesizer {
             stream_handle,
             sinks: HashMap::new(),
+            retiring: Vec::new(),
             pitch_bend_value: 0,
+            attack_secs: 0.01,
+            decay_secs: 0.1,
+            sustain_level: 0.8,
+            release_secs: 0.2,
+            soundfont: None,
+            tuning: None,
+            channel_programs: HashMap::new(),
+            channel_volumes: HashMap::new(),
+            sustain_pedal_down: HashMap::new(),
+            held_by_pedal: HashMap::new(),
         }
     }
 
-    fn note_on(&mut self, note: u8, velocity: u8) {
-        let base_frequency = midi_note_to_freq(note);
-        let frequency = self.apply_pitch_bend(base_frequency);
+    /// Loads a `.sf2` bank to render notes from instead of the built-in oscillator. If loading
+    /// fails, the synthesizer keeps synthesizing with the oscillator fallback.
+    fn load_soundfont(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        self.soundfont = Some(SoundFont::load(path)?);
+        Ok(())
+    }
+
+    /// Loads a Scala scale/keyboard mapping pair to tune notes with instead of 12-TET.
+    fn load_tuning(&mut self, scl_path: &Path, kbm_path: &Path) -> Result<(), Box<dyn Error>> {
+        self.tuning = Some(Tuning::load(scl_path, kbm_path)?);
+        Ok(())
+    }
+
+    fn base_freq(&self, note: u8) -> f32 {
+        match &self.tuning {
+            Some(tuning) => tuning.freq(note),
+            None => midi_note_to_freq(note),
+        }
+    }
+
+    fn program_change(&mut self, channel: u8, program: u8) {
+        self.channel_programs.insert(channel, program);
+    }
+
+    fn control_change(&mut self, channel: u8, controller: u8, value: u8) {
+        match controller {
+            CC_SUSTAIN_PEDAL => {
+                let pedal_down = value >= 64;
+                let was_down = self
+                    .sustain_pedal_down
+                    .insert(channel, pedal_down)
+                    .unwrap_or(false);
+                if was_down && !pedal_down {
+                    Self::release_pedal_held(&mut self.held_by_pedal, channel);
+                }
+            }
+            CC_CHANNEL_VOLUME => {
+                self.channel_volumes.insert(channel, value);
+                let channel_gain = value as f32 / 127.0;
+                for voice in self.sinks.values_mut().filter(|v| v.channel == channel) {
+                    voice.sink.set_volume(voice.velocity_gain * channel_gain);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Releases every gate being held for `channel` by the sustain pedal (called once it lifts).
+    fn release_pedal_held(held_by_pedal: &mut HashMap<u8, Vec<Arc<AtomicBool>>>, channel: u8) {
+        for gate in held_by_pedal.remove(&channel).unwrap_or_default() {
+            gate.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn note_on(&mut self, channel: u8, note: u8, velocity: u8) {
+        // Reclaim voices whose release phase has already finished playing.
+        self.sinks.retain(|_, voice| !voice.sink.empty());
+        self.retiring.retain(|voice| !voice.sink.empty());
+
+        // Retriggering a key that's still sounding (releasing, or held by the sustain pedal)
+        // mustn't drop its sink outright - that stops it instantly with no fade. Force it into
+        // release and let it keep playing out on its own instead.
+        Self::retire_if_present(&mut self.sinks, &mut self.retiring, (channel, note));
+
+        let base_frequency = self.base_freq(note);
         let sink = Sink::try_new(&self.stream_handle).unwrap();
-        sink.append(SineWave::new(frequency));
-        self.sinks.insert(note, (sink, base_frequency));
+        let velocity_gain = (velocity as f32 / 127.0).powi(2);
+        let channel_gain = *self.channel_volumes.get(&channel).unwrap_or(&127) as f32 / 127.0;
+        sink.set_volume(velocity_gain * channel_gain);
+        let gate = Arc::new(AtomicBool::new(true));
+
+        let program = *self.channel_programs.get(&channel).unwrap_or(&0);
+        let region = match &self.soundfont {
+            Some(sf) => sf.region_for(program, note),
+            None => None,
+        };
+        let frequency = self.apply_pitch_bend(base_frequency);
+
+        let freq_cell = if let Some(region) = region {
+            // The sample itself carries its own pitch via `root_key`; `frequency` still
+            // reflects the active tuning (and pitch bend), so tuning isn't lost just because a
+            // soundfont is loaded.
+            sink.append(soundfont::SoundFontVoice::new(
+                region,
+                frequency,
+                gate.clone(),
+                self.attack_secs,
+                self.decay_secs,
+                self.sustain_level,
+                self.release_secs,
+            ));
+            None
+        } else {
+            let freq_cell = Arc::new(AtomicU32::new(frequency.to_bits()));
+            sink.append(EnvelopedOscillator::new(
+                freq_cell.clone(),
+                gate.clone(),
+                self.attack_secs,
+                self.decay_secs,
+                self.sustain_level,
+                self.release_secs,
+            ));
+            Some(freq_cell)
+        };
+
+        self.sinks.insert(
+            (channel, note),
+            Voice {
+                sink,
+                channel,
+                base_frequency,
+                velocity_gain,
+                gate,
+                freq_cell,
+            },
+        );
+    }
+
+    fn note_off(&mut self, channel: u8, note: u8) {
+        if let Some(voice) = self.sinks.get(&(channel, note)) {
+            let pedal_down = *self.sustain_pedal_down.get(&channel).unwrap_or(&false);
+            Self::defer_or_release(
+                &mut self.held_by_pedal,
+                pedal_down,
+                channel,
+                voice.gate.clone(),
+            );
+        }
     }
 
-    fn note_off(&mut self, note: u8) {
-        if let Some((sink, _)) = self.sinks.remove(&note) {
-            sink.stop();
+    /// If the sustain pedal for `channel` is down, holds `gate`'s release until the pedal
+    /// lifts; otherwise releases it immediately. Deferring by the voice's own gate (rather than
+    /// its note) means a retrigger of the same key before the pedal lifts can't cause this to
+    /// release the wrong (newer) voice.
+    fn defer_or_release(
+        held_by_pedal: &mut HashMap<u8, Vec<Arc<AtomicBool>>>,
+        pedal_down: bool,
+        channel: u8,
+        gate: Arc<AtomicBool>,
+    ) {
+        if pedal_down {
+            held_by_pedal.entry(channel).or_default().push(gate);
+        } else {
+            gate.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// If a voice already occupies `key`, forces it into its release phase and moves it from
+    /// `sinks` into `retiring` so the new voice about to take its place doesn't cut it off.
+    fn retire_if_present(
+        sinks: &mut HashMap<(u8, u8), Voice>,
+        retiring: &mut Vec<Voice>,
+        key: (u8, u8),
+    ) {
+        if let Some(old_voice) = sinks.remove(&key) {
+            old_voice.gate.store(false, Ordering::Relaxed);
+            retiring.push(old_voice);
         }
     }
 
     fn pitch_bend_change(&mut self, value: u16) {
         self.pitch_bend_value = (value as i16) - 8192;
-        let changes: Vec<(u8, f32)> = self
-            .sinks
-            .iter()
-            .map(|(&note, &(_, base_frequency))| (note, self.apply_pitch_bend(base_frequency)))
-            .collect();
-
-        for (note, new_frequency) in changes {
-            if let Some((sink, _)) = self.sinks.get_mut(&note) {
-                sink.pause();
-                sink.clear();
-                sink.append(SineWave::new(new_frequency));
-                sink.play();
+        for voice in self.sinks.values() {
+            if let Some(freq_cell) = &voice.freq_cell {
+                let new_frequency = self.apply_pitch_bend(voice.base_frequency);
+                freq_cell.store(new_frequency.to_bits(), Ordering::Relaxed);
             }
         }
     }
@@ -169,3 +386,109 @@ impl Synthesizer {
 fn midi_note_to_freq(note: u8) -> f32 {
     440.0 * (2.0f32).powf((note as f32 - 69.0) / 12.0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `Voice` with a hardware-independent sink, suitable for exercising the pedal/retrigger
+    /// bookkeeping without a real `OutputStreamHandle`.
+    fn test_voice(channel: u8) -> Voice {
+        Voice {
+            sink: Sink::new_idle().0,
+            channel,
+            base_frequency: 440.0,
+            velocity_gain: 1.0,
+            gate: Arc::new(AtomicBool::new(true)),
+            freq_cell: None,
+        }
+    }
+
+    #[test]
+    fn retire_if_present_moves_existing_voice_into_retiring_and_releases_it() {
+        let mut sinks = HashMap::new();
+        let mut retiring = Vec::new();
+        let voice = test_voice(0);
+        let gate = voice.gate.clone();
+        sinks.insert((0, 60), voice);
+
+        Synthesizer::retire_if_present(&mut sinks, &mut retiring, (0, 60));
+
+        assert!(!sinks.contains_key(&(0, 60)));
+        assert_eq!(retiring.len(), 1);
+        assert!(!gate.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn retire_if_present_is_a_no_op_when_the_key_is_unoccupied() {
+        let mut sinks = HashMap::new();
+        let mut retiring = Vec::new();
+
+        Synthesizer::retire_if_present(&mut sinks, &mut retiring, (0, 60));
+
+        assert!(retiring.is_empty());
+    }
+
+    #[test]
+    fn defer_or_release_releases_immediately_when_the_pedal_is_up() {
+        let mut held_by_pedal = HashMap::new();
+        let gate = Arc::new(AtomicBool::new(true));
+
+        Synthesizer::defer_or_release(&mut held_by_pedal, false, 0, gate.clone());
+
+        assert!(!gate.load(Ordering::Relaxed));
+        assert!(held_by_pedal.is_empty());
+    }
+
+    #[test]
+    fn defer_or_release_holds_the_gate_open_while_the_pedal_is_down() {
+        let mut held_by_pedal = HashMap::new();
+        let gate = Arc::new(AtomicBool::new(true));
+
+        Synthesizer::defer_or_release(&mut held_by_pedal, true, 0, gate.clone());
+
+        assert!(gate.load(Ordering::Relaxed));
+        assert_eq!(held_by_pedal.get(&0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn release_pedal_held_releases_only_the_given_channel() {
+        let mut held_by_pedal = HashMap::new();
+        let gate0 = Arc::new(AtomicBool::new(true));
+        let gate1 = Arc::new(AtomicBool::new(true));
+        held_by_pedal.insert(0, vec![gate0.clone()]);
+        held_by_pedal.insert(1, vec![gate1.clone()]);
+
+        Synthesizer::release_pedal_held(&mut held_by_pedal, 0);
+
+        assert!(!gate0.load(Ordering::Relaxed));
+        assert!(gate1.load(Ordering::Relaxed));
+        assert!(!held_by_pedal.contains_key(&0));
+    }
+
+    #[test]
+    fn retriggering_a_pedal_held_note_does_not_let_the_pedal_release_the_new_voice() {
+        // Reproduces the bug the pedal/retrigger fix addressed: deferring a released note's
+        // gate by its own identity (rather than its note) means retriggering the same key
+        // while the pedal is still down, then lifting the pedal, releases the original
+        // (now-retiring) voice's gate without touching the new voice's gate.
+        let mut sinks = HashMap::new();
+        let mut retiring = Vec::new();
+        let mut held_by_pedal = HashMap::new();
+
+        let old_voice = test_voice(0);
+        let old_gate = old_voice.gate.clone();
+        sinks.insert((0, 60), old_voice);
+        Synthesizer::defer_or_release(&mut held_by_pedal, true, 0, old_gate.clone());
+
+        let new_voice = test_voice(0);
+        let new_gate = new_voice.gate.clone();
+        Synthesizer::retire_if_present(&mut sinks, &mut retiring, (0, 60));
+        sinks.insert((0, 60), new_voice);
+
+        Synthesizer::release_pedal_held(&mut held_by_pedal, 0);
+
+        assert!(!old_gate.load(Ordering::Relaxed));
+        assert!(new_gate.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,120 @@
+//! Interactive MIDI input port selection and a supervisor loop that reattaches to the chosen
+//! device by name if it disconnects, without restarting the program or losing synthesizer state.
+
+use midir::MidiInput;
+use std::error::Error;
+use std::io::{self, Write};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+/// How often to re-enumerate ports while connected (to notice a disconnect) or while waiting
+/// for a device to reappear.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Picks the MIDI input port to use: a case-insensitive substring match against `preferred` if
+/// given and found (so the choice can be scripted from the command line), otherwise an
+/// interactive index prompt listing every available port.
+pub fn select_port_name(preferred: Option<&str>) -> Result<String, Box<dyn Error>> {
+    let probe = MidiInput::new("midir port listing")?;
+    let ports = probe.ports();
+    if ports.is_empty() {
+        return Err("No available MIDI input ports.".into());
+    }
+
+    if let Some(filter) = preferred {
+        let filter_lower = filter.to_lowercase();
+        let matched = ports.iter().find(|port| {
+            probe
+                .port_name(port)
+                .map(|name| name.to_lowercase().contains(&filter_lower))
+                .unwrap_or(false)
+        });
+        if let Some(port) = matched {
+            return Ok(probe.port_name(port)?);
+        }
+        println!("No port matches '{}', falling back to manual selection.", filter);
+    }
+
+    println!("Available MIDI input ports:");
+    for (i, port) in ports.iter().enumerate() {
+        println!("  [{}] {}", i, probe.port_name(port)?);
+    }
+    print!("Select a port index [0]: ");
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let index = input.trim().parse::<usize>().unwrap_or(0).min(ports.len() - 1);
+    Ok(probe.port_name(&ports[index])?)
+}
+
+/// Connects to the port named `port_name` and calls `on_message` for every incoming message,
+/// reattaching by re-enumerating ports whenever the device disconnects, until `should_exit`
+/// reports that the user asked to quit.
+pub fn run_with_reconnect<F>(
+    port_name: &str,
+    should_exit: &Receiver<()>,
+    on_message: F,
+) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(u64, &[u8]) + Clone + Send + 'static,
+{
+    'reconnect: loop {
+        let mut midi_in = MidiInput::new("midir reading input")?;
+        midi_in.ignore(midir::Ignore::None);
+        let ports = midi_in.ports();
+        let port = ports
+            .iter()
+            .find(|p| midi_in.port_name(p).map(|n| n == port_name).unwrap_or(false));
+
+        let port = match port {
+            Some(port) => port,
+            None => {
+                println!("Waiting for '{}' to reconnect...", port_name);
+                if sleep_or_exit(should_exit) {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+
+        println!("Opening connection to port: {}", port_name);
+        let mut callback = on_message.clone();
+        let _conn = midi_in.connect(
+            port,
+            "midir-read-input",
+            move |stamp, message, _| callback(stamp, message),
+            (),
+        )?;
+        println!(
+            "Connection open, reading MIDI input from '{}'. Press Enter to exit...",
+            port_name
+        );
+
+        loop {
+            if should_exit.try_recv().is_ok() {
+                return Ok(());
+            }
+            if !port_still_present(port_name)? {
+                println!("'{}' disconnected, waiting to reattach...", port_name);
+                continue 'reconnect;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Sleeps one poll interval, returning `true` if an exit request arrived while waiting.
+fn sleep_or_exit(should_exit: &Receiver<()>) -> bool {
+    thread::sleep(POLL_INTERVAL);
+    should_exit.try_recv().is_ok()
+}
+
+fn port_still_present(port_name: &str) -> Result<bool, Box<dyn Error>> {
+    let probe = MidiInput::new("midir reconnect probe")?;
+    Ok(probe
+        .ports()
+        .iter()
+        .any(|p| probe.port_name(p).map(|n| n == port_name).unwrap_or(false)))
+}
@@ -0,0 +1,88 @@
+//! The synthesized (non-sampled) voice: an additive sine oscillator shaped by an ADSR envelope.
+
+use crate::envelope::EnvelopeState;
+use rodio::Source;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Relative amplitude of the fundamental and successive harmonics summed by
+/// [`EnvelopedOscillator`], each quieter than the last so the tone stays warm rather than buzzy.
+const PARTIAL_AMPLITUDES: [f32; 4] = [1.0, 0.5, 0.25, 0.125];
+
+const SAMPLE_RATE: u32 = 48000;
+
+/// An additive oscillator (fundamental plus a few decaying harmonics) whose amplitude is
+/// shaped by an attack/decay/sustain/release envelope. `freq` is a shared cell (holding the
+/// bits of an `f32`) rather than a plain field so a live voice can be retuned in place - e.g.
+/// by pitch bend - without disturbing its envelope or gate.
+pub struct EnvelopedOscillator {
+    freq: Arc<AtomicU32>,
+    sample_idx: u64,
+    envelope: EnvelopeState,
+}
+
+impl EnvelopedOscillator {
+    pub fn new(
+        freq: Arc<AtomicU32>,
+        gate: Arc<AtomicBool>,
+        attack_secs: f32,
+        decay_secs: f32,
+        sustain_level: f32,
+        release_secs: f32,
+    ) -> Self {
+        EnvelopedOscillator {
+            freq,
+            sample_idx: 0,
+            envelope: EnvelopeState::new(
+                SAMPLE_RATE,
+                gate,
+                attack_secs,
+                decay_secs,
+                sustain_level,
+                release_secs,
+            ),
+        }
+    }
+}
+
+impl Iterator for EnvelopedOscillator {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let envelope = self.envelope.next_level()?;
+        let freq = f32::from_bits(self.freq.load(Ordering::Relaxed));
+        let t = self.sample_idx as f32 / SAMPLE_RATE as f32;
+
+        let norm: f32 = PARTIAL_AMPLITUDES.iter().sum();
+        let partials: f32 = PARTIAL_AMPLITUDES
+            .iter()
+            .enumerate()
+            .map(|(i, amplitude)| {
+                let harmonic = (i + 1) as f32;
+                (2.0 * std::f32::consts::PI * freq * harmonic * t).sin() * amplitude
+            })
+            .sum();
+
+        self.sample_idx += 1;
+        Some(partials / norm * envelope)
+    }
+}
+
+impl Source for EnvelopedOscillator {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
@@ -0,0 +1,113 @@
+//! Captures incoming MIDI messages and writes them out as a Standard MIDI File (format 0).
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Ticks per quarter note used for the `MThd` division field.
+const TICKS_PER_QUARTER: u32 = 480;
+
+/// Fixed tempo assumed when converting recorded microsecond timestamps to ticks (120 BPM).
+const MICROS_PER_QUARTER: u64 = 500_000;
+
+/// Records `(timestamp_us, raw_message)` pairs as they arrive and can serialize them to a
+/// `.mid` file on request.
+pub struct MidiRecorder {
+    events: Vec<(u64, Vec<u8>)>,
+}
+
+impl MidiRecorder {
+    pub fn new() -> Self {
+        MidiRecorder { events: Vec::new() }
+    }
+
+    /// Records a decoded message together with the timestamp (in microseconds) it arrived at.
+    pub fn record(&mut self, stamp_us: u64, message: &[u8]) {
+        if message.is_empty() {
+            return;
+        }
+        self.events.push((stamp_us, message.to_vec()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Writes the captured events to `path` as a format-0 Standard MIDI File.
+    pub fn write_smf(&self, path: &Path) -> io::Result<()> {
+        let mut track = Vec::new();
+        let mut prev_stamp_us = self.events.first().map_or(0, |&(stamp, _)| stamp);
+
+        for (stamp_us, message) in &self.events {
+            let delta_us = stamp_us.saturating_sub(prev_stamp_us);
+            prev_stamp_us = *stamp_us;
+            let delta_ticks = (delta_us * TICKS_PER_QUARTER as u64 / MICROS_PER_QUARTER) as u32;
+            write_vlq(&mut track, delta_ticks);
+            track.extend_from_slice(message);
+        }
+
+        // End of track meta event.
+        write_vlq(&mut track, 0);
+        track.extend_from_slice(&[0xFF, 0x2F, 0x00]);
+
+        let mut file = File::create(path)?;
+        file.write_all(b"MThd")?;
+        file.write_all(&6u32.to_be_bytes())?;
+        file.write_all(&0u16.to_be_bytes())?; // format 0
+        file.write_all(&1u16.to_be_bytes())?; // ntrks
+        file.write_all(&(TICKS_PER_QUARTER as u16).to_be_bytes())?;
+
+        file.write_all(b"MTrk")?;
+        file.write_all(&(track.len() as u32).to_be_bytes())?;
+        file.write_all(&track)?;
+
+        Ok(())
+    }
+}
+
+/// Writes `value` as a variable-length quantity: 7 bits per byte, big-endian, with the high
+/// bit set on every byte but the last.
+fn write_vlq(buf: &mut Vec<u8>, value: u32) {
+    let mut chunks = [
+        ((value >> 21) & 0x7F) as u8,
+        ((value >> 14) & 0x7F) as u8,
+        ((value >> 7) & 0x7F) as u8,
+        (value & 0x7F) as u8,
+    ];
+
+    let first_significant = chunks.iter().position(|&b| b != 0).unwrap_or(3);
+    let last = chunks.len() - 1;
+    for byte in &mut chunks[first_significant..last] {
+        *byte |= 0x80;
+    }
+    buf.extend_from_slice(&chunks[first_significant..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_vlq;
+
+    fn vlq(value: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_vlq(&mut buf, value);
+        buf
+    }
+
+    #[test]
+    fn single_byte_values_pass_through_unset() {
+        assert_eq!(vlq(0x00), vec![0x00]);
+        assert_eq!(vlq(0x40), vec![0x40]);
+        assert_eq!(vlq(0x7F), vec![0x7F]);
+    }
+
+    #[test]
+    fn multi_byte_values_set_the_continuation_bit() {
+        assert_eq!(vlq(0x80), vec![0x81, 0x00]);
+        assert_eq!(vlq(0x2000), vec![0xC0, 0x00]);
+        assert_eq!(vlq(0x3FFF), vec![0xFF, 0x7F]);
+        assert_eq!(vlq(0x4000), vec![0x81, 0x80, 0x00]);
+        assert_eq!(vlq(0x1FFFFF), vec![0xFF, 0xFF, 0x7F]);
+        assert_eq!(vlq(0x200000), vec![0x81, 0x80, 0x80, 0x00]);
+        assert_eq!(vlq(0x0FFFFFFF), vec![0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+}
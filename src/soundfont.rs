@@ -0,0 +1,395 @@
+//! A minimal SoundFont2 (.sf2) reader.
+//!
+//! This walks just enough of the RIFF preset/instrument/sample hierarchy to pick a sample
+//! region for a given program and MIDI key - it does not implement the full generator and
+//! modulator graph the spec allows (loop modes, velocity zones, filters, etc).
+
+use crate::envelope::EnvelopeState;
+use rodio::Source;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+const GEN_KEY_RANGE: u16 = 43;
+const GEN_INSTRUMENT: u16 = 41;
+const GEN_SAMPLE_ID: u16 = 53;
+const GEN_OVERRIDING_ROOT_KEY: u16 = 58;
+
+#[derive(Debug)]
+pub struct SoundFontError(String);
+
+impl fmt::Display for SoundFontError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed soundfont: {}", self.0)
+    }
+}
+
+impl Error for SoundFontError {}
+
+/// One played-back region of PCM sample data: the raw samples plus the parameters needed to
+/// pitch and loop them correctly for a given MIDI key.
+#[derive(Clone)]
+pub struct SampleRegion {
+    pub samples: Vec<i16>,
+    pub sample_rate: u32,
+    pub root_key: u8,
+    pub loop_start: u32,
+    pub loop_end: u32,
+}
+
+struct InstrumentZone {
+    key_lo: u8,
+    key_hi: u8,
+    sample_id: u16,
+    root_key_override: Option<u8>,
+}
+
+struct SampleHeader {
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_rate: u32,
+    orig_pitch: u8,
+}
+
+/// A loaded SoundFont bank, indexed for quick `(bank, program, key)` -> sample lookups.
+pub struct SoundFont {
+    // (bank, program) -> instrument indices used by that preset's zones.
+    presets: HashMap<(u16, u16), Vec<usize>>,
+    instruments: Vec<Vec<InstrumentZone>>,
+    sample_headers: Vec<SampleHeader>,
+    sample_data: Vec<i16>,
+}
+
+impl SoundFont {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let data = fs::read(path)?;
+        Self::parse(&data)
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let riff = chunks(data)
+            .into_iter()
+            .find(|(id, _)| id == b"RIFF")
+            .ok_or_else(|| SoundFontError("missing RIFF chunk".into()))?
+            .1;
+        if riff.len() < 4 {
+            return Err(Box::new(SoundFontError("RIFF chunk too short".into())));
+        }
+        if &riff[0..4] != b"sfbk" {
+            return Err(Box::new(SoundFontError("not an sfbk file".into())));
+        }
+
+        let mut sample_data = Vec::new();
+        let mut phdr = Vec::new();
+        let mut pbag = Vec::new();
+        let mut pgen = Vec::new();
+        let mut inst = Vec::new();
+        let mut ibag = Vec::new();
+        let mut igen = Vec::new();
+        let mut shdr = Vec::new();
+
+        for (list_id, list_payload) in chunks(&riff[4..]) {
+            if &list_id != b"LIST" || list_payload.len() < 4 {
+                continue;
+            }
+            let list_type = &list_payload[0..4];
+            let body = &list_payload[4..];
+
+            match list_type {
+                b"sdta" => {
+                    if let Some((_, smpl)) = chunks(body).into_iter().find(|(id, _)| id == b"smpl")
+                    {
+                        sample_data = smpl
+                            .chunks_exact(2)
+                            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                            .collect();
+                    }
+                }
+                b"pdta" => {
+                    for (id, payload) in chunks(body) {
+                        match &id {
+                            b"phdr" => phdr = payload.chunks_exact(38).collect(),
+                            b"pbag" => pbag = payload.chunks_exact(4).collect(),
+                            b"pgen" => pgen = payload.chunks_exact(4).collect(),
+                            b"inst" => inst = payload.chunks_exact(22).collect(),
+                            b"ibag" => ibag = payload.chunks_exact(4).collect(),
+                            b"igen" => igen = payload.chunks_exact(4).collect(),
+                            b"shdr" => shdr = payload.chunks_exact(46).collect(),
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Every phdr/inst array (and their bag/gen arrays) ends with a terminal sentinel
+        // record whose only purpose is to give the previous record's zone range an upper
+        // bound, so real entry counts are always `len() - 1`.
+        let instruments = parse_instrument_zones(&inst, &ibag, &igen);
+        let presets = parse_preset_instruments(&phdr, &pbag, &pgen);
+        let sample_headers = shdr
+            .iter()
+            .take(shdr.len().saturating_sub(1))
+            .map(|b| SampleHeader {
+                start: u32::from_le_bytes(b[20..24].try_into().unwrap()),
+                end: u32::from_le_bytes(b[24..28].try_into().unwrap()),
+                loop_start: u32::from_le_bytes(b[28..32].try_into().unwrap()),
+                loop_end: u32::from_le_bytes(b[32..36].try_into().unwrap()),
+                sample_rate: u32::from_le_bytes(b[36..40].try_into().unwrap()),
+                orig_pitch: b[40],
+            })
+            .collect();
+
+        Ok(SoundFont {
+            presets,
+            instruments,
+            sample_headers,
+            sample_data,
+        })
+    }
+
+    /// Returns the sample region that should sound for `program` (bank 0) when `key` is
+    /// struck, or `None` if this bank has no matching preset/zone (the caller should fall
+    /// back to the synthesized oscillator in that case).
+    pub fn region_for(&self, program: u8, key: u8) -> Option<SampleRegion> {
+        let instrument_indices = self.presets.get(&(0, program as u16))?;
+
+        for &instrument_idx in instrument_indices {
+            let zones = self.instruments.get(instrument_idx)?;
+            if let Some(zone) = zones
+                .iter()
+                .find(|z| key >= z.key_lo && key <= z.key_hi)
+            {
+                let header = self.sample_headers.get(zone.sample_id as usize)?;
+                let start = header.start as usize;
+                let end = (header.end as usize).min(self.sample_data.len());
+                if start >= end {
+                    continue;
+                }
+                return Some(SampleRegion {
+                    samples: self.sample_data[start..end].to_vec(),
+                    sample_rate: header.sample_rate,
+                    root_key: zone.root_key_override.unwrap_or(header.orig_pitch),
+                    loop_start: header.loop_start.saturating_sub(header.start),
+                    loop_end: header.loop_end.saturating_sub(header.start),
+                });
+            }
+        }
+        None
+    }
+}
+
+fn parse_instrument_zones(
+    inst: &[&[u8]],
+    ibag: &[&[u8]],
+    igen: &[&[u8]],
+) -> Vec<Vec<InstrumentZone>> {
+    if inst.len() < 2 {
+        return Vec::new();
+    }
+
+    let bag_ndx = |rec: &[u8]| u16::from_le_bytes(rec[20..22].try_into().unwrap());
+    let gen_ndx = |rec: &[u8]| u16::from_le_bytes(rec[0..2].try_into().unwrap());
+
+    (0..inst.len() - 1)
+        .map(|i| {
+            let bag_lo = (bag_ndx(inst[i]) as usize).min(ibag.len());
+            let bag_hi = (bag_ndx(inst[i + 1]) as usize).min(ibag.len().saturating_sub(1));
+            let mut zones = Vec::new();
+            for j in bag_lo..bag_hi {
+                let gen_lo = gen_ndx(ibag[j]) as usize;
+                let gen_hi = (gen_ndx(ibag[j + 1]) as usize).min(igen.len());
+                let gens = igen.get(gen_lo..gen_hi).unwrap_or(&[]);
+                if let Some(zone) = parse_zone_generators(gens) {
+                    zones.push(zone);
+                }
+            }
+            zones
+        })
+        .collect()
+}
+
+/// Reads the generators of a single instrument zone. Returns `None` for the (optional)
+/// global zone, which carries defaults/modulators but no `sampleID` generator.
+fn parse_zone_generators(gens: &[&[u8]]) -> Option<InstrumentZone> {
+    let mut key_lo = 0u8;
+    let mut key_hi = 127u8;
+    let mut sample_id = None;
+    let mut root_key_override = None;
+
+    for gen in gens {
+        let oper = u16::from_le_bytes(gen[0..2].try_into().unwrap());
+        match oper {
+            GEN_KEY_RANGE => {
+                key_lo = gen[2];
+                key_hi = gen[3];
+            }
+            GEN_OVERRIDING_ROOT_KEY => {
+                root_key_override = Some(u16::from_le_bytes(gen[2..4].try_into().unwrap()) as u8);
+            }
+            GEN_SAMPLE_ID => {
+                sample_id = Some(u16::from_le_bytes(gen[2..4].try_into().unwrap()));
+            }
+            _ => {}
+        }
+    }
+
+    sample_id.map(|sample_id| InstrumentZone {
+        key_lo,
+        key_hi,
+        sample_id,
+        root_key_override,
+    })
+}
+
+fn parse_preset_instruments(
+    phdr: &[&[u8]],
+    pbag: &[&[u8]],
+    pgen: &[&[u8]],
+) -> HashMap<(u16, u16), Vec<usize>> {
+    let mut presets = HashMap::new();
+    if phdr.len() < 2 {
+        return presets;
+    }
+
+    let preset_num = |rec: &[u8]| u16::from_le_bytes(rec[20..22].try_into().unwrap());
+    let bank_num = |rec: &[u8]| u16::from_le_bytes(rec[22..24].try_into().unwrap());
+    let bag_ndx = |rec: &[u8]| u16::from_le_bytes(rec[24..26].try_into().unwrap());
+    let gen_ndx = |rec: &[u8]| u16::from_le_bytes(rec[0..2].try_into().unwrap());
+
+    for i in 0..phdr.len() - 1 {
+        let bag_lo = (bag_ndx(phdr[i]) as usize).min(pbag.len());
+        let bag_hi = (bag_ndx(phdr[i + 1]) as usize).min(pbag.len().saturating_sub(1));
+        let mut instruments = Vec::new();
+        for j in bag_lo..bag_hi {
+            let gen_lo = gen_ndx(pbag[j]) as usize;
+            let gen_hi = (gen_ndx(pbag[j + 1]) as usize).min(pgen.len());
+            for gen in pgen.get(gen_lo..gen_hi).unwrap_or(&[]) {
+                let oper = u16::from_le_bytes(gen[0..2].try_into().unwrap());
+                if oper == GEN_INSTRUMENT {
+                    let idx = u16::from_le_bytes(gen[2..4].try_into().unwrap()) as usize;
+                    instruments.push(idx);
+                }
+            }
+        }
+        presets.insert((bank_num(phdr[i]), preset_num(phdr[i])), instruments);
+    }
+    presets
+}
+
+/// Splits a buffer into sequential `(chunk_id, payload)` pairs. Chunks are word-aligned, so a
+/// chunk with an odd payload size is followed by one padding byte that isn't part of any
+/// payload.
+fn chunks(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let id: [u8; 4] = data[pos..pos + 4].try_into().unwrap();
+        let size = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let start = pos + 8;
+        let end = (start + size).min(data.len());
+        out.push((id, &data[start..end]));
+        pos = end + (size % 2);
+    }
+    out
+}
+
+/// Plays a [`SampleRegion`] back at a target frequency, looping over the sustain loop points
+/// for as long as the note is held, shaped by the same ADSR envelope as
+/// [`crate::oscillator::EnvelopedOscillator`] so sampled and synthesized voices fade the same way.
+pub struct SoundFontVoice {
+    region: SampleRegion,
+    playback_rate: f32,
+    pos: f32,
+    envelope: EnvelopeState,
+}
+
+impl SoundFontVoice {
+    /// `target_frequency` is the already-tuned frequency (from [`crate::tuning::Tuning`] or
+    /// plain 12-TET, with pitch bend applied) the sample should sound at; the sample itself is
+    /// always recorded against a 12-TET `root_key`, so the playback rate is their ratio.
+    pub fn new(
+        region: SampleRegion,
+        target_frequency: f32,
+        gate: Arc<AtomicBool>,
+        attack_secs: f32,
+        decay_secs: f32,
+        sustain_level: f32,
+        release_secs: f32,
+    ) -> Self {
+        let root_frequency = crate::midi_note_to_freq(region.root_key);
+        let playback_rate = target_frequency / root_frequency;
+        let sample_rate = region.sample_rate;
+        SoundFontVoice {
+            region,
+            playback_rate,
+            pos: 0.0,
+            envelope: EnvelopeState::new(
+                sample_rate,
+                gate,
+                attack_secs,
+                decay_secs,
+                sustain_level,
+                release_secs,
+            ),
+        }
+    }
+
+    fn sample_at(&self, index: usize) -> f32 {
+        self.region
+            .samples
+            .get(index)
+            .map(|&s| s as f32 / i16::MAX as f32)
+            .unwrap_or(0.0)
+    }
+}
+
+impl Iterator for SoundFontVoice {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let envelope = self.envelope.next_level()?;
+
+        let has_loop = self.region.loop_end > self.region.loop_start;
+        if has_loop && self.pos as u32 >= self.region.loop_end {
+            self.pos -= (self.region.loop_end - self.region.loop_start) as f32;
+        }
+        if !has_loop && self.pos as usize >= self.region.samples.len() {
+            return None;
+        }
+
+        let index = self.pos as usize;
+        let frac = self.pos.fract();
+        let sample = self.sample_at(index) * (1.0 - frac) + self.sample_at(index + 1) * frac;
+
+        self.pos += self.playback_rate;
+        Some(sample * envelope)
+    }
+}
+
+impl Source for SoundFontVoice {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.region.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
@@ -0,0 +1,213 @@
+//! Scala (`.scl`/`.kbm`) microtonal tuning support.
+//!
+//! A `.scl` file lists the cents (or ratio) offset of each scale degree above the root, ending
+//! with the period (usually, but not always, 1200¢ for octave-repeating scales). A `.kbm` file
+//! says which MIDI key is the tuning's origin, which key is pinned to a reference frequency, and
+//! optionally an explicit, non-identity mapping from keys to scale degrees for generalized
+//! keyboards. This only implements the common identity-mapping case fully; an explicit mapping
+//! that leaves a key unmapped (`x`) falls back to stepping the identity mapping for that key
+//! rather than silencing it, since this synth always expects a frequency for a struck note.
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Parsed `.kbm` keyboard mapping.
+struct KeyboardMapping {
+    map_size: usize,
+    middle_note: u8,
+    ref_note: u8,
+    ref_freq: f64,
+    /// `mapping[i]` is the scale degree that key `middle_note + i` (repeating every `map_size`
+    /// keys) maps to, or `None` for "x" (unmapped). Empty when `map_size` is 0, meaning the
+    /// identity mapping.
+    mapping: Vec<Option<u32>>,
+}
+
+/// A tuning table built from a Scala scale and keyboard mapping, used in place of 12-TET.
+pub struct Tuning {
+    /// Cents offset of scale degrees 1..=n above the root (degree 0 is implicitly 0¢).
+    degree_cents: Vec<f64>,
+    /// Cents spanned by one full repeat of the scale (the `.scl` file's last entry).
+    period_cents: f64,
+    keyboard: KeyboardMapping,
+}
+
+impl Tuning {
+    pub fn load(scl_path: &Path, kbm_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let scl = fs::read_to_string(scl_path)?;
+        let mut degree_cents = parse_scl(&scl)?;
+        let period_cents = degree_cents
+            .pop()
+            .ok_or("scale file has no degrees")?;
+
+        let kbm = fs::read_to_string(kbm_path)?;
+        let keyboard = parse_kbm(&kbm)?;
+
+        Ok(Tuning {
+            degree_cents,
+            period_cents,
+            keyboard,
+        })
+    }
+
+    /// Resolves the frequency of `note` under this tuning.
+    pub fn freq(&self, note: u8) -> f32 {
+        let note_cents = self.cents_for_key(note);
+        let ref_cents = self.cents_for_key(self.keyboard.ref_note);
+        let total_cents = note_cents - ref_cents;
+        (self.keyboard.ref_freq * 2f64.powf(total_cents / 1200.0)) as f32
+    }
+
+    /// Cents offset of `key` above the keyboard mapping's middle note.
+    fn cents_for_key(&self, key: u8) -> f64 {
+        // One full period spans this many scale degrees (0..=n-1); degree n wraps to degree 0
+        // of the next period.
+        let scale_size = self.degree_cents.len() as i64 + 1;
+        let relative = key as i64 - self.keyboard.middle_note as i64;
+
+        let degree = if self.keyboard.map_size == 0 {
+            relative
+        } else {
+            let map_size = self.keyboard.map_size as i64;
+            let idx = relative.rem_euclid(map_size) as usize;
+            let cycles = relative.div_euclid(map_size);
+            match self.keyboard.mapping.get(idx).copied().flatten() {
+                Some(mapped_degree) => cycles * scale_size + mapped_degree as i64,
+                None => relative,
+            }
+        };
+
+        let octaves = degree.div_euclid(scale_size);
+        let degree_in_period = degree.rem_euclid(scale_size) as usize;
+        let degree_cents = if degree_in_period == 0 {
+            0.0
+        } else {
+            self.degree_cents[degree_in_period - 1]
+        };
+        octaves as f64 * self.period_cents + degree_cents
+    }
+}
+
+/// Parses a `.scl` file's degree list (cents or ratios), including the trailing period entry.
+fn parse_scl(content: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+    let mut lines = content.lines().filter(|line| !line.trim_start().starts_with('!'));
+
+    lines.next().ok_or("scl file missing description line")?;
+    let count: usize = lines
+        .next()
+        .ok_or("scl file missing note count")?
+        .split_whitespace()
+        .next()
+        .ok_or("scl file has an empty note count line")?
+        .parse()?;
+
+    let degrees: Vec<f64> = lines
+        .filter_map(|line| line.split_whitespace().next())
+        .take(count)
+        .map(parse_degree)
+        .collect::<Result<_, _>>()?;
+
+    if degrees.len() != count {
+        return Err(format!("scl file declares {} degrees but has {}", count, degrees.len()).into());
+    }
+    Ok(degrees)
+}
+
+/// Parses one scale degree: cents if it contains a decimal point, otherwise a ratio (`a/b`, or
+/// a bare integer `a` meaning `a/1`).
+fn parse_degree(token: &str) -> Result<f64, Box<dyn Error>> {
+    if token.contains('.') {
+        Ok(token.parse::<f64>()?)
+    } else if let Some((num, den)) = token.split_once('/') {
+        Ok(1200.0 * (num.parse::<f64>()? / den.parse::<f64>()?).log2())
+    } else {
+        Ok(1200.0 * token.parse::<f64>()?.log2())
+    }
+}
+
+fn parse_kbm(content: &str) -> Result<KeyboardMapping, Box<dyn Error>> {
+    let mut lines = content
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('!'))
+        .map(str::trim);
+
+    let mut next = |what: &str| -> Result<String, Box<dyn Error>> {
+        Ok(lines
+            .next()
+            .ok_or_else(|| format!("kbm file missing {}", what))?
+            .to_string())
+    };
+
+    let map_size: usize = next("map size")?.parse()?;
+    let _first_note: u8 = next("first note")?.parse()?;
+    let _last_note: u8 = next("last note")?.parse()?;
+    let middle_note: u8 = next("middle note")?.parse()?;
+    let ref_note: u8 = next("reference note")?.parse()?;
+    let ref_freq: f64 = next("reference frequency")?.parse()?;
+    let _octave_degree: usize = next("formal octave degree")?.parse()?;
+
+    let mapping = lines
+        .take(map_size)
+        .map(|line| if line == "x" { None } else { line.parse::<u32>().ok() })
+        .collect();
+
+    Ok(KeyboardMapping {
+        map_size,
+        middle_note,
+        ref_note,
+        ref_freq,
+        mapping,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_degree, KeyboardMapping, Tuning};
+
+    #[test]
+    fn parse_degree_reads_cents_ratios_and_bare_integers() {
+        assert_eq!(parse_degree("700.0").unwrap(), 700.0);
+        assert!((parse_degree("3/2").unwrap() - 701.955).abs() < 0.001);
+        assert_eq!(parse_degree("2").unwrap(), 1200.0);
+    }
+
+    /// An equal-tempered, identity-mapped 12-TET tuning pinned to 440Hz at its middle note, for
+    /// exercising [`Tuning::cents_for_key`]'s octave math without parsing any files.
+    fn twelve_tet() -> Tuning {
+        Tuning {
+            degree_cents: (1..12).map(|step| step as f64 * 100.0).collect(),
+            period_cents: 1200.0,
+            keyboard: KeyboardMapping {
+                map_size: 0,
+                middle_note: 60,
+                ref_note: 60,
+                ref_freq: 440.0,
+                mapping: Vec::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn identity_mapping_matches_equal_temperament() {
+        let tuning = twelve_tet();
+        assert_eq!(tuning.freq(60), 440.0);
+        assert_eq!(tuning.freq(72), 880.0); // one octave up
+        assert_eq!(tuning.freq(48), 220.0); // one octave down
+        assert!((tuning.freq(61) - 440.0 * 2f32.powf(1.0 / 12.0)).abs() < 0.01);
+    }
+
+    #[test]
+    fn explicit_mapping_falls_back_to_identity_for_unmapped_keys() {
+        let mut tuning = twelve_tet();
+        tuning.keyboard.map_size = 3;
+        // Key 0 (the reference note) maps to degree 0 so it stays the tuning's 440Hz anchor.
+        tuning.keyboard.mapping = vec![Some(0), None, Some(8)];
+
+        // Key 1 of the map is "x" (unmapped), so it falls back to stepping the identity
+        // mapping - degree 1 relative to the middle note - rather than going silent.
+        assert_eq!(tuning.freq(61), (440.0 * 2f64.powf(100.0 / 1200.0)) as f32);
+        // Key 2 of the map is explicitly mapped to degree 8.
+        assert_eq!(tuning.freq(62), (440.0 * 2f64.powf(800.0 / 1200.0)) as f32);
+    }
+}